@@ -1,22 +1,30 @@
 extern crate clap;
+extern crate cpal;
+extern crate rhai;
 extern crate standard_midi_file;
 extern crate synthesizer;
 
 use clap::{App, Arg};
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use rhai::{Engine, Scope, AST};
 use standard_midi_file::header::TimeScale;
 use standard_midi_file::track::event::Event;
 use standard_midi_file::SMF;
 use std::collections::HashMap;
 use std::fs::File;
-use std::io::{BufReader, BufWriter};
+use std::io::{BufReader, BufWriter, Write};
 use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use synthesizer::envelope::Envelope;
 use synthesizer::frequency_lookup::MIDIFrequencyLookup;
 use synthesizer::helper::SequenceHelper;
 use synthesizer::instrument::Instrument;
 use synthesizer::key_generator::{
-    SawtoothWaveGenerator, SquareWaveGenerator, TriangleWaveGenerator,
+    KeyGenerator, SawtoothWaveGenerator, SquareWaveGenerator, TriangleWaveGenerator,
 };
-use synthesizer::pcm::PCMParameters;
+use synthesizer::pcm::{PCMParameters, PCM};
 use synthesizer::util::Volume;
 use synthesizer::wave::{SampleType, Wave};
 use synthesizer::Synthesizer;
@@ -48,6 +56,336 @@ fn calc_time(ticks: u32, tempo: u32, ticks_per_quarter_note: u16) -> f64 {
     (f64::from(tempo) / f64::from(ticks_per_quarter_note)) * f64::from(ticks) * 10f64.powi(-6)
 }
 
+struct ChannelTimeline<T: Copy> {
+    data: Vec<(u32, u8, T)>,
+}
+
+impl<T: Copy> ChannelTimeline<T> {
+    fn new() -> ChannelTimeline<T> {
+        ChannelTimeline { data: Vec::new() }
+    }
+    fn record(&mut self, tick: u32, channel: u8, value: T) {
+        self.data.push((tick, channel, value));
+    }
+    fn at(&mut self, channel: u8, tick: u32, default: T) -> T {
+        self.data.sort_by_key(|&(at_tick, _, _)| at_tick);
+        self.data.reverse();
+        for &(at_tick, at_channel, value) in &self.data {
+            if at_channel == channel && tick >= at_tick {
+                return value;
+            }
+        }
+        default
+    }
+}
+
+/// 0-indexed MIDI channel reserved by General MIDI for percussion.
+const PERCUSSION_CHANNEL: u8 = 9;
+
+fn gm_generator(program: u8) -> Box<dyn KeyGenerator> {
+    match program {
+        40..=55 => Box::new(SawtoothWaveGenerator {}),
+        72..=79 => Box::new(TriangleWaveGenerator {}),
+        _ => Box::new(SquareWaveGenerator {}),
+    }
+}
+
+fn generator_by_name(name: &str) -> Box<dyn KeyGenerator> {
+    match name {
+        "triangle" => Box::new(TriangleWaveGenerator {}),
+        "sawtooth" => Box::new(SawtoothWaveGenerator {}),
+        _ => Box::new(SquareWaveGenerator {}),
+    }
+}
+
+fn generator_for(
+    function_override: Option<&str>,
+    script: Option<&ScriptConfig>,
+    channel: u8,
+    program: u8,
+) -> Box<dyn KeyGenerator> {
+    if let Some((name, _)) = script.and_then(|s| s.instrument_for(channel, program)) {
+        return generator_by_name(&name);
+    }
+    match function_override {
+        Some("triangle") => Box::new(TriangleWaveGenerator {}),
+        Some("sawtooth") => Box::new(SawtoothWaveGenerator {}),
+        Some("square") => Box::new(SquareWaveGenerator {}),
+        _ => gm_generator(program),
+    }
+}
+
+fn envelope_for(program: u8) -> Envelope {
+    match program {
+        16..=23 | 40..=55 | 88..=95 => Envelope {
+            attack: 0.3,
+            decay: 0.2,
+            sustain: 0.8,
+            release: 0.5,
+        },
+        _ => Envelope {
+            attack: 0.01,
+            decay: 0.08,
+            sustain: 0.7,
+            release: 0.1,
+        },
+    }
+}
+
+trait PcmSink {
+    fn write(&self, pcm: PCM, path: &Path);
+}
+
+struct WavSink;
+
+impl PcmSink for WavSink {
+    fn write(&self, pcm: PCM, path: &Path) {
+        let wave = Wave {
+            pcm,
+            sample_type: SampleType::Signed16,
+        };
+        let mut writer = BufWriter::new(File::create(path).unwrap());
+        wave.write(&mut writer).unwrap();
+    }
+}
+
+struct FfmpegSink;
+
+impl PcmSink for FfmpegSink {
+    fn write(&self, pcm: PCM, path: &Path) {
+        let mut child = Command::new("ffmpeg")
+            .args(&[
+                "-y",
+                "-f",
+                "s16le",
+                "-ar",
+                &pcm.params.sample_rate.to_string(),
+                "-ac",
+                &pcm.params.nb_channels.to_string(),
+                "-i",
+                "-",
+            ])
+            .arg(path)
+            .stdin(Stdio::piped())
+            .spawn()
+            .expect("failed to spawn ffmpeg");
+
+        {
+            let stdin = child.stdin.take().expect("ffmpeg stdin not piped");
+            let mut stdin = BufWriter::new(stdin);
+            for sample in &pcm.data {
+                let clamped = (sample * f64::from(i16::max_value()))
+                    .max(f64::from(i16::min_value()))
+                    .min(f64::from(i16::max_value())) as i16;
+                stdin.write_all(&clamped.to_le_bytes()).unwrap();
+            }
+            stdin.flush().unwrap();
+            // Drop `stdin` here to close ffmpeg's input pipe; otherwise it
+            // blocks waiting for EOF and `child.wait()` below never returns.
+        }
+
+        let status = child.wait().expect("ffmpeg did not run");
+        if !status.success() {
+            panic!("ffmpeg exited with {}", status);
+        }
+    }
+}
+
+fn sink_for(path: &Path) -> Box<dyn PcmSink> {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("mp3") | Some("flac") | Some("ogg") => Box::new(FfmpegSink),
+        _ => Box::new(WavSink),
+    }
+}
+
+fn play_pcm(pcm: &PCM) {
+    let host = cpal::default_host();
+    let device = host
+        .default_output_device()
+        .expect("no output device available");
+    let config = cpal::StreamConfig {
+        channels: pcm.params.nb_channels,
+        sample_rate: cpal::SampleRate(pcm.params.sample_rate),
+        buffer_size: cpal::BufferSize::Default,
+    };
+
+    let samples: Vec<f32> = pcm.data.iter().map(|&s| s as f32).collect();
+    let cursor = Arc::new(AtomicUsize::new(0));
+    let finished = Arc::new((Mutex::new(false), Condvar::new()));
+
+    let callback_cursor = cursor.clone();
+    let callback_finished = finished.clone();
+    let stream = device
+        .build_output_stream(
+            &config,
+            move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
+                let start = callback_cursor.fetch_add(data.len(), Ordering::SeqCst);
+                for (i, out) in data.iter_mut().enumerate() {
+                    *out = samples.get(start + i).copied().unwrap_or(0.0);
+                }
+                if start + data.len() >= samples.len() {
+                    let (done, cvar) = &*callback_finished;
+                    *done.lock().unwrap() = true;
+                    cvar.notify_all();
+                }
+            },
+            |err| eprintln!("Playback error: {}", err),
+        )
+        .unwrap();
+    stream.play().unwrap();
+
+    let (done, cvar) = &*finished;
+    let mut done = done.lock().unwrap();
+    while !*done {
+        done = cvar.wait(done).unwrap();
+    }
+}
+
+fn stereo_volumes(
+    velocity: u8,
+    volume_scale: f64,
+    channel_volume: u8,
+    channel_pan: u8,
+) -> (f64, f64) {
+    let cc_volume = f64::from(channel_volume) / 127.0;
+    // volume_scale comes from an untrusted --config script, so clamp it.
+    let base = ((f64::from(velocity) / 128f64) * volume_scale * cc_volume)
+        .max(0.0)
+        .min(1.0);
+    let pan = f64::from(channel_pan) / 127.0;
+    let theta = pan * std::f64::consts::FRAC_PI_2;
+    (base * theta.cos(), base * theta.sin())
+}
+
+// Stops a note immediately, unless the channel's CC64 sustain pedal is held,
+// in which case the stop is deferred until the pedal is released.
+fn stop_note(
+    seq_builder: &mut SequenceHelper,
+    channel: u8,
+    key: usize,
+    voice: usize,
+    held: bool,
+    sustained_stops: &mut HashMap<u8, Vec<(usize, usize)>>,
+) {
+    if held {
+        sustained_stops
+            .entry(channel)
+            .or_insert_with(Vec::new)
+            .push((key, voice));
+    } else {
+        seq_builder.stop_note(key, voice).unwrap();
+    }
+}
+
+fn voice_id(
+    channel: u8,
+    program: u8,
+    voices: &mut HashMap<(u8, u8), (usize, f64)>,
+    script: Option<&ScriptConfig>,
+) -> Option<(usize, f64)> {
+    if channel == PERCUSSION_CHANNEL {
+        return None;
+    }
+    let next_id = voices.len();
+    let volume_scale = script
+        .and_then(|s| s.instrument_for(channel, program))
+        .map_or(1.0, |(_, scale)| scale);
+    Some(
+        *voices
+            .entry((channel, program))
+            .or_insert((next_id, volume_scale)),
+    )
+}
+
+struct ScriptConfig {
+    engine: Engine,
+    ast: AST,
+}
+
+impl ScriptConfig {
+    fn load(path: &Path) -> ScriptConfig {
+        let engine = Engine::new();
+        let ast = engine
+            .compile_file(path.to_path_buf())
+            .expect("failed to compile config script");
+        ScriptConfig { engine, ast }
+    }
+
+    // Rhai has no tuple type, so the script returns [name, scale] as an
+    // array rather than a Rust tuple call_fn could cast to directly.
+    fn instrument_for(&self, channel: u8, program: u8) -> Option<(String, f64)> {
+        let array: rhai::Array = self
+            .engine
+            .call_fn(
+                &mut Scope::new(),
+                &self.ast,
+                "instrument_for",
+                (i64::from(channel), i64::from(program)),
+            )
+            .ok()?;
+        let mut iter = array.into_iter();
+        let name = iter.next()?.cast::<String>();
+        let scale = iter.next()?.as_float().ok()?;
+        Some((name, scale))
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        let rate: i64 = self
+            .engine
+            .call_fn(&mut Scope::new(), &self.ast, "sample_rate", ())
+            .ok()?;
+        Some(rate as u32)
+    }
+
+    fn channels(&self) -> Option<u16> {
+        let channels: i64 = self
+            .engine
+            .call_fn(&mut Scope::new(), &self.ast, "channels", ())
+            .ok()?;
+        Some(channels as u16)
+    }
+}
+
+enum Timing {
+    Metrical { ticks_per_quarter_note: u16 },
+    SMPTE { seconds_per_tick: f64 },
+}
+
+impl Timing {
+    fn new(time_division: TimeScale) -> Timing {
+        match time_division {
+            TimeScale::TicksPerQuarterNote(t) => Timing::Metrical {
+                ticks_per_quarter_note: t,
+            },
+            TimeScale::SMPTECompatible(fps_code, ticks_per_frame) => {
+                let fps = match fps_code {
+                    -24 => 24f64,
+                    -25 => 25f64,
+                    -29 => 29.97f64,
+                    -30 => 30f64,
+                    _ => panic!("Unknown SMPTE frame rate code: {}", fps_code),
+                };
+                Timing::SMPTE {
+                    seconds_per_tick: 1.0 / (fps * f64::from(ticks_per_frame)),
+                }
+            }
+        }
+    }
+
+    fn calc_time(&self, ticks: u32, at_tick: u32, tempo_helper: &mut TempoHelper) -> f64 {
+        match self {
+            Timing::Metrical {
+                ticks_per_quarter_note,
+            } => calc_time(
+                ticks,
+                tempo_helper.get_tempo(at_tick),
+                *ticks_per_quarter_note,
+            ),
+            Timing::SMPTE { seconds_per_tick } => f64::from(ticks) * seconds_per_tick,
+        }
+    }
+}
+
 fn main() {
     let matches = App::new("MIDI Synthesizer")
         .version("0.1")
@@ -61,40 +399,74 @@ fn main() {
         )
         .arg(
             Arg::with_name("OUTPUT")
-                .help("Output .wav file")
-                .required(true)
+                .help("Output file: .mp3, .flac and .ogg are encoded through ffmpeg, anything else is written as WAV. Optional if --play is given.")
+                .required(false)
                 .index(2),
         )
         .arg(
             Arg::with_name("FUNCTION")
-                .help("Chooses the sound generator function. Possible values are 'square', 'triangles', 'sawtooth'.")
-                .required(false)
-                .index(3),
+                .long("function")
+                .value_name("FUNCTION")
+                .help("Forces every instrument to use this sound generator instead of the General MIDI mapping, except where a --config script's instrument_for returns a generator. Possible values are 'square', 'triangle', 'sawtooth'.")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("CONFIG")
+                .long("config")
+                .value_name("FILE")
+                .help("Rhai script overriding instrument selection and PCM parameters")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("PLAY")
+                .long("play")
+                .help("Streams the synthesized audio to the default output device")
+                .takes_value(false),
         )
         .get_matches();
 
     let input_str = matches.value_of("INPUT").unwrap();
-    let output_str = matches.value_of("OUTPUT").unwrap();
+    let output_str = matches.value_of("OUTPUT");
+    let play = matches.is_present("PLAY");
+    if output_str.is_none() && !play {
+        eprintln!("Either an OUTPUT file or --play must be given");
+        std::process::exit(1);
+    }
     let input_path = Path::new(input_str);
-    let output_path = Path::new(output_str);
+    let script_config = matches
+        .value_of("CONFIG")
+        .map(|path| ScriptConfig::load(Path::new(path)));
 
     // Open the MIDI File
     let smf = SMF::import(&mut BufReader::new(File::open(input_path).unwrap())).unwrap();
-    let tpqn = match smf.header.time_division {
-        TimeScale::TicksPerQuarterNote(t) => t,
-        TimeScale::SMPTECompatible(_, _) => unimplemented!(),
-    };
+    let timing = Timing::new(smf.header.time_division);
 
     // Create Tempo Helper
     let mut tempo_helper = TempoHelper::new();
 
-    // Find all tempos first
+    // Tracks play concurrently, so channel state is resolved from a timeline
+    // built across all tracks up front, the same way tempo is.
+    let mut program_timeline: ChannelTimeline<u8> = ChannelTimeline::new();
+    let mut volume_timeline: ChannelTimeline<u8> = ChannelTimeline::new();
+    let mut pan_timeline: ChannelTimeline<u8> = ChannelTimeline::new();
+    let mut sustain_timeline: ChannelTimeline<bool> = ChannelTimeline::new();
+
+    // Find all tempos and channel state first
     for track in &smf.tracks {
         let mut at_tick = 0;
         for track_event in &track.track_events {
             at_tick += track_event.delta_time.value;
             match track_event.event {
                 Event::Tempo(t) => tempo_helper.new_tempo(at_tick, t.value),
+                Event::ProgramChange(p) => program_timeline.record(at_tick, p.channel, p.program),
+                Event::ControlChange(c) => match c.controller {
+                    7 => volume_timeline.record(at_tick, c.channel, c.value),
+                    10 => pan_timeline.record(at_tick, c.channel, c.value),
+                    64 => sustain_timeline.record(at_tick, c.channel, c.value >= 64),
+                    _ => {}
+                },
                 _ => {}
             }
         }
@@ -103,50 +475,118 @@ fn main() {
     // Create a Sequence Helper
     let mut seq_builder = SequenceHelper::new();
 
+    // The instrument id each (channel, program) pair has been assigned.
+    let mut voices: HashMap<(u8, u8), (usize, f64)> = HashMap::new();
+
+    // Voice a currently-sounding note started on, keyed by (channel, key).
+    let mut active_notes: HashMap<(u8, u8), usize> = HashMap::new();
+
+    // Note stops a held sustain pedal (CC64) has deferred, by channel.
+    let mut sustained_stops: HashMap<u8, Vec<(usize, usize)>> = HashMap::new();
+
     // Go through everything
     for track in &smf.tracks {
         seq_builder.reset();
         let mut at_tick = 0;
         for track_event in &track.track_events {
             at_tick += track_event.delta_time.value;
-            seq_builder.time_forward(calc_time(
+            seq_builder.time_forward(timing.calc_time(
                 track_event.delta_time.value,
-                tempo_helper.get_tempo(at_tick),
-                tpqn,
+                at_tick,
+                &mut tempo_helper,
             ));
             match track_event.event {
                 Event::NoteOn(n) => {
                     if n.velocity > 0 {
-                        seq_builder
-                            .start_note(
-                                usize::from(n.key),
-                                0,
-                                vec![Volume::new(f64::from(n.velocity) / 128f64).unwrap()],
-                            )
-                            .unwrap();
-                    } else {
-                        seq_builder.stop_note(usize::from(n.key), 0).unwrap();
+                        let program = program_timeline.at(n.channel, at_tick, 0);
+                        if let Some((voice, volume_scale)) =
+                            voice_id(n.channel, program, &mut voices, script_config.as_ref())
+                        {
+                            active_notes.insert((n.channel, n.key), voice);
+                            let channel_volume = volume_timeline.at(n.channel, at_tick, 127);
+                            let channel_pan = pan_timeline.at(n.channel, at_tick, 64);
+                            let (left, right) = stereo_volumes(
+                                n.velocity,
+                                volume_scale,
+                                channel_volume,
+                                channel_pan,
+                            );
+                            seq_builder
+                                .start_note(
+                                    usize::from(n.key),
+                                    voice,
+                                    vec![Volume::new(left).unwrap(), Volume::new(right).unwrap()],
+                                )
+                                .unwrap();
+                        }
+                    } else if let Some(voice) = active_notes.remove(&(n.channel, n.key)) {
+                        let held = sustain_timeline.at(n.channel, at_tick, false);
+                        stop_note(
+                            &mut seq_builder,
+                            n.channel,
+                            usize::from(n.key),
+                            voice,
+                            held,
+                            &mut sustained_stops,
+                        );
+                    }
+                }
+                Event::NoteOff(n) => {
+                    if let Some(voice) = active_notes.remove(&(n.channel, n.key)) {
+                        let held = sustain_timeline.at(n.channel, at_tick, false);
+                        stop_note(
+                            &mut seq_builder,
+                            n.channel,
+                            usize::from(n.key),
+                            voice,
+                            held,
+                            &mut sustained_stops,
+                        );
+                    }
+                }
+                Event::ControlChange(c) if c.controller == 64 && c.value < 64 => {
+                    if let Some(pending) = sustained_stops.remove(&c.channel) {
+                        for (key, voice) in pending {
+                            seq_builder.stop_note(key, voice).unwrap();
+                        }
                     }
                 }
-                Event::NoteOff(n) => seq_builder.stop_note(usize::from(n.key), 0).unwrap(),
                 _ => {}
             }
         }
     }
 
-    let mut inst = HashMap::with_capacity(1);
-    inst.insert(
-        0,
-        Instrument {
-            keys: HashMap::new(),
-            key_gen: match matches.value_of("FUNCTION").unwrap_or("") {
-                "triangle" => Box::new(TriangleWaveGenerator {}),
-                "sawtooth" => Box::new(SawtoothWaveGenerator {}),
-                _ => Box::new(SquareWaveGenerator {}),
+    let mut inst = HashMap::with_capacity(voices.len().max(1));
+    if voices.is_empty() {
+        // No melodic note was started (the file is empty or only uses the
+        // percussion channel): keep the single flat instrument 0.
+        inst.insert(
+            0,
+            Instrument {
+                keys: HashMap::new(),
+                key_gen: generator_for(matches.value_of("FUNCTION"), script_config.as_ref(), 0, 0),
+                loopable: false,
+                envelope: envelope_for(0),
             },
-            loopable: false,
-        },
-    );
+        );
+    } else {
+        for (&(channel, program), &(id, _)) in &voices {
+            inst.insert(
+                id,
+                Instrument {
+                    keys: HashMap::new(),
+                    key_gen: generator_for(
+                        matches.value_of("FUNCTION"),
+                        script_config.as_ref(),
+                        channel,
+                        program,
+                    ),
+                    loopable: false,
+                    envelope: envelope_for(program),
+                },
+            );
+        }
+    }
 
     // Create the Synth
     let mut synth = Synthesizer {
@@ -154,21 +594,26 @@ fn main() {
         inst,
         f_lu: Box::new(MIDIFrequencyLookup {}),
         params: PCMParameters {
-            sample_rate: 44100,
-            nb_channels: 1,
+            sample_rate: script_config
+                .as_ref()
+                .and_then(ScriptConfig::sample_rate)
+                .unwrap_or(44100),
+            nb_channels: script_config
+                .as_ref()
+                .and_then(ScriptConfig::channels)
+                .unwrap_or(2),
         },
     };
 
     // Run the Synth
     let pcm = synth.run().unwrap();
 
-    // Create the Wave file
-    let wave = Wave {
-        pcm,
-        sample_type: SampleType::Signed16,
-    };
-
-    let mut writer = BufWriter::new(File::create(output_path).unwrap());
+    if play {
+        play_pcm(&pcm);
+    }
 
-    wave.write(&mut writer).unwrap();
+    if let Some(output_str) = output_str {
+        let output_path = Path::new(output_str);
+        sink_for(output_path).write(pcm, output_path);
+    }
 }